@@ -1,5 +1,6 @@
 use std::ffi::OsStr;
 use std::fmt::{Debug};
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use tar::Archive;
@@ -7,6 +8,484 @@ use flate2::read::{GzDecoder};
 
 type Error = Box<dyn std::error::Error>;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl std::str::FromStr for Compression {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            "xz" => Ok(Compression::Xz),
+            other => Err(format!("Unknown compression codec: {}", other).into()),
+        }
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Gzip
+    }
+}
+
+impl Compression {
+    fn magic(&self) -> &'static [u8] {
+        match self {
+            Compression::Gzip => &[0x1f, 0x8b],
+            Compression::Zstd => &[0x28, 0xb5, 0x2f, 0xfd],
+            Compression::Xz => &[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00],
+        }
+    }
+
+    fn detect_bytes(head: &[u8]) -> Result<Self, Error> {
+        for codec in [Compression::Gzip, Compression::Zstd, Compression::Xz] {
+            if head.starts_with(codec.magic()) {
+                return Ok(codec);
+            }
+        }
+        Err("Unrecognized archive format".into())
+    }
+
+    fn detect(archive_path: &str) -> Result<Self, Error> {
+        let mut file = std::fs::File::open(archive_path)?;
+        let mut head = [0u8; 6];
+        let read = read_fully(&mut file, &mut head)?;
+        Self::detect_bytes(&head[..read])
+            .map_err(|_| format!("Unrecognized archive format for {}", archive_path).into())
+    }
+
+    fn compress_cmd(&self, level: u32, threads: u32, window: Option<u32>) -> String {
+        match self {
+            Compression::Gzip => format!("gzip -{}", level),
+            Compression::Zstd => {
+                let long = window.map(|w| format!(" --long={}", w)).unwrap_or_default();
+                format!("zstd -T{}{} -{}", threads, long, level)
+            }
+            Compression::Xz => {
+                let dict = window.map(|w| format!(" --lzma2=preset={},dict={}MiB", level, w)).unwrap_or_default();
+                format!("xz -T{} -{}{}", threads, level, dict)
+            }
+        }
+    }
+
+    fn decompress_cmd(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip -dc",
+            // --long=31 admits any window the compressor may have used.
+            Compression::Zstd => "zstd -dc --long=31",
+            Compression::Xz => "xz -dc",
+        }
+    }
+
+    fn decoder<'a, R: Read + 'a>(&self, reader: R) -> Result<Box<dyn Read + 'a>, Error> {
+        match self {
+            Compression::Gzip => Ok(Box::new(GzDecoder::new(reader))),
+            Compression::Zstd => {
+                let mut decoder = zstd::stream::read::Decoder::new(reader)?;
+                decoder.window_log_max(31)?;
+                Ok(Box::new(decoder))
+            }
+            Compression::Xz => Ok(Box::new(xz2::read::XzDecoder::new(reader))),
+        }
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+fn wait_ok(child: &mut std::process::Child) -> Result<(), Error> {
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(format!("helper container exited with {}", status).into());
+    }
+    Ok(())
+}
+
+fn required_binaries(compression: Compression) -> Vec<&'static str> {
+    let codec = match compression {
+        Compression::Gzip => "gzip",
+        Compression::Zstd => "zstd",
+        Compression::Xz => "xz",
+    };
+    vec!["tar", codec]
+}
+
+fn validate_image(image: &str, binaries: &[&str]) -> Result<(), Error> {
+    let check = binaries
+        .iter()
+        .map(|bin| format!("command -v {} >/dev/null 2>&1 || {{ echo \"missing {}\" >&2; exit 1; }}", bin, bin))
+        .collect::<Vec<_>>()
+        .join("; ");
+    let status = std::process::Command::new("docker")
+        .arg("run")
+        .arg("--rm")
+        .arg(image)
+        .arg("sh")
+        .arg("-c")
+        .arg(check)
+        .status()?;
+    if !status.success() {
+        return Err(format!("Helper image {} is missing one of the required binaries {:?}", image, binaries).into());
+    }
+    Ok(())
+}
+
+fn layer_tar_bytes(media_type: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+    if media_type.contains("gzip") {
+        let mut out = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut out)?;
+        Ok(out)
+    } else {
+        Ok(data.to_vec())
+    }
+}
+
+fn append_tar_bytes<W: std::io::Write>(builder: &mut tar::Builder<W>, name: &str, data: &[u8]) -> Result<(), Error> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, name, data)?;
+    Ok(())
+}
+
+fn staged_tag(image: &str) -> String {
+    use sha2::{Digest, Sha256};
+    let digest = format!("{:x}", Sha256::digest(image.as_bytes()));
+    format!("dvm-staged:{}", &digest[..12])
+}
+
+fn pull_image(image: &str) -> Result<String, Error> {
+    use oci_distribution::client::{Client, ClientConfig};
+    use oci_distribution::manifest;
+    use oci_distribution::secrets::RegistryAuth;
+    use oci_distribution::Reference;
+    use sha2::{Digest, Sha256};
+
+    let reference: Reference = image.parse().map_err(|e| format!("Invalid image reference {}: {}", image, e))?;
+    let client = Client::new(ClientConfig::default());
+    let runtime = tokio::runtime::Runtime::new()?;
+    let data = runtime
+        .block_on(client.pull(
+            &reference,
+            &RegistryAuth::Anonymous,
+            vec![
+                manifest::IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+                manifest::IMAGE_LAYER_MEDIA_TYPE,
+            ],
+        ))
+        .map_err(|e| format!("Failed to pull {}: {}", image, e))?;
+
+    // A digest ref is not a valid RepoTags entry, so run a local staged tag.
+    let tag = staged_tag(image);
+
+    let mut child = std::process::Command::new("docker")
+        .arg("load")
+        .stdin(std::process::Stdio::piped())
+        .spawn()?;
+    let stdin = child.stdin.take().ok_or("Failed to capture docker stdin")?;
+    let mut builder = tar::Builder::new(stdin);
+
+    let mut layer_names = Vec::new();
+    for layer in &data.layers {
+        let tar_bytes = layer_tar_bytes(&layer.media_type, &layer.data)?;
+        let name = format!("{:x}/layer.tar", Sha256::digest(&tar_bytes));
+        append_tar_bytes(&mut builder, &name, &tar_bytes)?;
+        layer_names.push(name);
+    }
+
+    let config = &data.config.data;
+    let config_name = format!("{:x}.json", Sha256::digest(config));
+    append_tar_bytes(&mut builder, &config_name, config)?;
+
+    let layers_json = layer_names.iter().map(|n| format!("{:?}", n)).collect::<Vec<_>>().join(",");
+    let manifest_json = format!(
+        "[{{\"Config\":{:?},\"RepoTags\":[{:?}],\"Layers\":[{}]}}]",
+        config_name, tag, layers_json,
+    );
+    append_tar_bytes(&mut builder, "manifest.json", manifest_json.as_bytes())?;
+    builder.finish()?;
+    drop(builder);
+    if !child.wait()?.success() {
+        return Err(format!("docker load failed while staging {}", image).into());
+    }
+    Ok(tag)
+}
+
+fn prepare_image(image: &str, pull: bool, binaries: &[&str]) -> Result<String, Error> {
+    let run_ref = if pull {
+        pull_image(image)?
+    } else {
+        image.to_string()
+    };
+    validate_image(&run_ref, binaries)?;
+    Ok(run_ref)
+}
+
+const ENC_MAGIC: &[u8; 7] = b"DVMENC1";
+const ENC_VERSION: u8 = 1;
+const FRAME_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct Argon2Params {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        // OWASP-recommended second-choice profile (19 MiB, 2 passes).
+        Argon2Params { m_cost: 19_456, t_cost: 2, p_cost: 1 }
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], params: Argon2Params) -> Result<[u8; 32], Error> {
+    use argon2::{Algorithm, Argon2, Params, Version};
+
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| format!("Invalid Argon2 parameters: {}", e))?;
+    let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+    let mut key = [0u8; 32];
+    argon
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+fn frame_nonce(base: &[u8; 24], counter: u32, last: bool) -> [u8; 24] {
+    let mut nonce = *base;
+    nonce[19..23].copy_from_slice(&counter.to_be_bytes());
+    nonce[23] = if last { 1 } else { 0 };
+    nonce
+}
+
+fn get_passphrase() -> Result<String, Error> {
+    match std::env::var("DVM_PASSPHRASE") {
+        Ok(p) => Ok(p),
+        Err(_) => Ok(rpassword::prompt_password("Passphrase: ")?),
+    }
+}
+
+fn encrypt_stream<R: Read, W: std::io::Write>(mut reader: R, mut writer: W, passphrase: &str) -> Result<(), Error> {
+    use chacha20poly1305::aead::{Aead, KeyInit};
+    use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+    use rand::RngCore;
+
+    let mut salt = [0u8; 16];
+    let mut base_nonce = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut base_nonce);
+
+    let params = Argon2Params::default();
+    let key = derive_key(passphrase, &salt, params)?;
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+
+    writer.write_all(ENC_MAGIC)?;
+    writer.write_all(&[ENC_VERSION])?;
+    writer.write_all(&params.m_cost.to_be_bytes())?;
+    writer.write_all(&params.t_cost.to_be_bytes())?;
+    writer.write_all(&params.p_cost.to_be_bytes())?;
+    writer.write_all(&salt)?;
+    writer.write_all(&base_nonce)?;
+
+    let mut buf = vec![0u8; FRAME_SIZE];
+    let mut counter: u32 = 0;
+    loop {
+        let n = read_fully(&mut reader, &mut buf)?;
+        let last = n < FRAME_SIZE;
+        let nonce = frame_nonce(&base_nonce, counter, last);
+        let ciphertext = cipher
+            .encrypt(XNonce::from_slice(&nonce), &buf[..n])
+            .map_err(|_| "Encryption failed")?;
+        writer.write_all(&(ciphertext.len() as u32).to_be_bytes())?;
+        writer.write_all(&ciphertext)?;
+        counter += 1;
+        if last {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+struct EncHeader {
+    params: Argon2Params,
+    salt: [u8; 16],
+    base_nonce: [u8; 24],
+}
+
+impl EncHeader {
+    fn read<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let mut magic = [0u8; 7];
+        read_fully(reader, &mut magic)?;
+        if &magic != ENC_MAGIC {
+            return Err("Not an encrypted archive".into());
+        }
+        let mut version = [0u8; 1];
+        read_fully(reader, &mut version)?;
+        if version[0] != ENC_VERSION {
+            return Err(format!("Unsupported encryption version: {}", version[0]).into());
+        }
+        let params = Argon2Params {
+            m_cost: read_u32(reader)?,
+            t_cost: read_u32(reader)?,
+            p_cost: read_u32(reader)?,
+        };
+        let mut salt = [0u8; 16];
+        read_fully(reader, &mut salt)?;
+        let mut base_nonce = [0u8; 24];
+        read_fully(reader, &mut base_nonce)?;
+        Ok(EncHeader { params, salt, base_nonce })
+    }
+}
+
+struct DecryptReader<R: Read> {
+    reader: R,
+    cipher: chacha20poly1305::XChaCha20Poly1305,
+    base_nonce: [u8; 24],
+    counter: u32,
+    prefetched_len: Option<u32>,
+    finished: bool,
+    current: std::io::Cursor<Vec<u8>>,
+}
+
+impl<R: Read> DecryptReader<R> {
+    fn new(reader: R, passphrase: &str) -> Result<Self, Error> {
+        let mut reader = reader;
+        let header = EncHeader::read(&mut reader)?;
+        let key = derive_key(passphrase, &header.salt, header.params)?;
+        Self::with_key(reader, &header, &key)
+    }
+
+    fn with_key(mut reader: R, header: &EncHeader, key: &[u8; 32]) -> Result<Self, Error> {
+        use chacha20poly1305::aead::KeyInit;
+        use chacha20poly1305::{Key, XChaCha20Poly1305};
+
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+
+        // Prime the one-frame read-ahead used to detect the final frame.
+        let prefetched_len = read_u32_or_eof(&mut reader)?;
+        Ok(DecryptReader {
+            reader,
+            cipher,
+            base_nonce: header.base_nonce,
+            counter: 0,
+            prefetched_len,
+            finished: false,
+            current: std::io::Cursor::new(Vec::new()),
+        })
+    }
+
+    fn fill(&mut self) -> Result<bool, Error> {
+        use chacha20poly1305::aead::Aead;
+        use chacha20poly1305::XNonce;
+
+        if self.finished {
+            return Ok(false);
+        }
+        let len = match self.prefetched_len.take() {
+            Some(len) => len,
+            None => return Err("Encrypted stream truncated before final frame".into()),
+        };
+        let mut ciphertext = vec![0u8; len as usize];
+        if read_fully(&mut self.reader, &mut ciphertext)? != len as usize {
+            return Err("Encrypted stream truncated mid-frame".into());
+        }
+        // A frame is the last one iff no further frame follows it.
+        self.prefetched_len = read_u32_or_eof(&mut self.reader)?;
+        let last = self.prefetched_len.is_none();
+        let nonce = frame_nonce(&self.base_nonce, self.counter, last);
+        let plaintext = self
+            .cipher
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| "Authentication failed: archive is corrupt or tampered with")?;
+        self.counter += 1;
+        self.current = std::io::Cursor::new(plaintext);
+        if last {
+            self.finished = true;
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.current.read(out)?;
+            if n > 0 {
+                return Ok(n);
+            }
+            match self.fill() {
+                Ok(true) => continue,
+                Ok(false) => return Ok(0),
+                Err(e) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())),
+            }
+        }
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, Error> {
+    let mut buf = [0u8; 4];
+    if read_fully(reader, &mut buf)? != 4 {
+        return Err("Unexpected end of encrypted header".into());
+    }
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u32_or_eof<R: Read>(reader: &mut R) -> Result<Option<u32>, Error> {
+    let mut buf = [0u8; 4];
+    let n = read_fully(reader, &mut buf)?;
+    match n {
+        0 => Ok(None),
+        4 => Ok(Some(u32::from_be_bytes(buf))),
+        _ => Err("Encrypted stream truncated at frame length".into()),
+    }
+}
+
+fn is_encrypted(archive_path: &str) -> Result<bool, Error> {
+    let mut file = std::fs::File::open(archive_path)?;
+    let mut magic = [0u8; 7];
+    let n = read_fully(&mut file, &mut magic)?;
+    Ok(n == magic.len() && &magic == ENC_MAGIC)
+}
+
+fn sniff_decoder<'a, R: Read + 'a>(mut reader: R) -> Result<Box<dyn Read + 'a>, Error> {
+    let mut head = [0u8; 6];
+    let n = read_fully(&mut reader, &mut head)?;
+    let codec = Compression::detect_bytes(&head[..n])?;
+    let prefix = std::io::Cursor::new(head[..n].to_vec());
+    codec.decoder(prefix.chain(reader))
+}
+
+fn open_archive_reader(archive_path: &str) -> Result<Box<dyn Read>, Error> {
+    let file = std::fs::File::open(archive_path)?;
+    if is_encrypted(archive_path)? {
+        let passphrase = get_passphrase()?;
+        let decrypted = DecryptReader::new(file, &passphrase)?;
+        sniff_decoder(decrypted)
+    } else {
+        sniff_decoder(file)
+    }
+}
+
 fn os_str_to_str<T: AsRef<OsStr> + Debug>(os_str: &T) -> String {
     let os_str = os_str.as_ref();
     os_str.to_str().and_then(|str| Some(str.to_string())).unwrap_or_else(|| {
@@ -14,6 +493,22 @@ fn os_str_to_str<T: AsRef<OsStr> + Debug>(os_str: &T) -> String {
    })
 }
 
+trait ToUtf8 {
+    fn to_utf8(&self) -> Result<&str, Error>;
+}
+
+impl ToUtf8 for OsStr {
+    fn to_utf8(&self) -> Result<&str, Error> {
+        self.to_str().ok_or_else(|| format!("Path is not valid UTF-8: {:?}", self).into())
+    }
+}
+
+impl ToUtf8 for Path {
+    fn to_utf8(&self) -> Result<&str, Error> {
+        self.as_os_str().to_utf8()
+    }
+}
+
 fn resolve_path(path_str: &str, create: bool) -> Result<(String, String), Error> {
 
     if create && !Path::new(path_str).exists(){
@@ -24,21 +519,29 @@ fn resolve_path(path_str: &str, create: bool) -> Result<(String, String), Error>
         format!("Failed to resolve path {}, {}", path_str, e)
     })?;
 
-    let parent = &canonical.parent().ok_or(
+    let parent = canonical.parent().ok_or_else(||
         format!("Failed to get parent path of path: {:?}", os_str_to_str(&canonical))
-    )?.to_str().ok_or(
-        format!("Failed to convert parent path of path to string: {:?}", os_str_to_str(&canonical))
-    )?;
-    let filename = &canonical.file_name().ok_or(
+    )?.to_utf8()?;
+    let filename = canonical.file_name().ok_or_else(||
         format!("Failed to get filename of target path: {:?}", os_str_to_str(&canonical))
-    )?.to_str().ok_or(
-        format!("Failed to convert filename of target path to string: {:?}", os_str_to_str(&canonical))
-    )?;
+    )?.to_utf8()?;
 
     Ok((parent.to_string(), filename.to_string()))
 }
 
-fn backup(volume_names: Vec<&str>, target_str: &str) -> Result<(), Error>{
+fn backup(
+    volume_names: Vec<&str>,
+    target_str: &str,
+    compression: Compression,
+    level: u32,
+    threads: u32,
+    window: Option<u32>,
+    encrypt: bool,
+    image: &str,
+    pull: bool,
+) -> Result<(), Error>{
+
+    let image = prepare_image(image, pull, &required_binaries(compression))?;
 
     let volume_args = volume_names.iter().map(|volume_name| format!("--volume={}:/input/{}:ro", volume_name, volume_name));
 
@@ -46,30 +549,69 @@ fn backup(volume_names: Vec<&str>, target_str: &str) -> Result<(), Error>{
 
     let (target_parent_abs, target_filename) = resolve_path(target_str, true)?;
 
-    std::process::Command::new("docker")
+    if encrypt {
+        // Seal in Rust so the passphrase never reaches the helper image.
+        let pipeline = format!(
+            "tar -cf - -C /input . | {}",
+            compression.compress_cmd(level, threads, window),
+        );
+        let mut child = std::process::Command::new("docker")
+            .arg("run")
+            .arg("--rm")
+            .args(volume_args)
+            .arg(&image)
+            .arg("sh")
+            .arg("-c")
+            .arg(pipeline)
+            .stdout(std::process::Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or("Failed to capture docker stdout")?;
+        let passphrase = get_passphrase()?;
+        // Promote the temp file to the target only on a clean container exit.
+        let target = Path::new(&target_parent_abs).join(&target_filename);
+        let tmp = Path::new(&target_parent_abs).join(format!("{}.partial", target_filename));
+        let writer = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+        encrypt_stream(stdout, writer, &passphrase)?;
+        if let Err(e) = wait_ok(&mut child) {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+        std::fs::rename(&tmp, &target)?;
+        return Ok(());
+    }
+
+    // Pipe the tar stream through the chosen compressor.
+    let pipeline = format!(
+        "tar -cf - -C /input . | {} > /output/{}",
+        compression.compress_cmd(level, threads, window),
+        shell_quote(&target_filename),
+    );
+
+    let mut child = std::process::Command::new("docker")
         .arg("run")
         .arg("--rm")
         .arg(format!("--volume={}:/output", target_parent_abs))
         .args(volume_args)
-        .arg("alpine")
-        .arg("tar")
-        .arg("-czf")
-        .arg(format!("/output/{}", target_filename))
-        .arg("-C")
-        .arg("/input")
-        .arg(".")
-        .spawn()?
-        .wait()?;
+        .arg(&image)
+        .arg("sh")
+        .arg("-c")
+        .arg(pipeline)
+        .spawn()?;
+    wait_ok(&mut child)?;
     Ok(())
 }
 
+fn tar_entry_paths<R: Read>(reader: R) -> Result<Vec<PathBuf>, Error>{
+    let mut archive = Archive::new(reader);
+    let mut paths = Vec::new();
+    for entry in archive.entries()? {
+        paths.push(entry?.path()?.into_owned());
+    }
+    Ok(paths)
+}
+
 fn get_tar_directory_tree(archive_path: &str) -> Result<Vec<PathBuf>, Error>{
-    let decoder = GzDecoder::new(std::fs::File::open(archive_path)?);
-    let mut archive = Archive::new(decoder);
-    let entries = archive.entries()?;
-    let entries = entries.collect::<Result<Vec<_>, _>>()?;
-    let entries = entries.iter().map(|entry| entry.path().unwrap().into_owned()).collect::<Vec<_>>();
-    Ok(entries)
+    tar_entry_paths(open_archive_reader(archive_path)?)
 }
 
 trait NegativeIndex<T> {
@@ -91,43 +633,461 @@ impl<T> NegativeIndex<T> for Vec<T> {
     }
 }
 
+fn volume_of(path: &Path) -> Option<&OsStr> {
+    path
+        .ancestors()
+        .collect::<Vec<_>>()
+        .neg_index(-3)
+        .and_then(|path| path.file_name())
+}
+
+fn top_level_from_tree(dir_tree: &[PathBuf]) -> Result<Vec<String>, Error> {
+    let mut volume_names: Vec<String> = Vec::new();
+    for path in dir_tree {
+        if let Some(name) = volume_of(path) {
+            let name = name.to_utf8()?.to_string();
+            // consecutive-dedup, matching the archive's entry ordering
+            if volume_names.last() != Some(&name) {
+                volume_names.push(name);
+            }
+        }
+    }
+    Ok(volume_names)
+}
+
 fn get_tar_top_level_list(archive_path: &str) -> Result<Vec<String>, Error>{
     let dir_tree = get_tar_directory_tree(archive_path)?;
-    let mut volume_names = dir_tree.iter().map(|path|
-        path
-            .ancestors()
-            .collect::<Vec<_>>()
-            .neg_index(-3)
-            .and_then(|path| path.file_name())
-            .and_then(|path| path.to_str())
-    ).filter_map(|path| path)
-        .collect::<Vec<_>>();
-    // unique
-    volume_names.dedup();
-    Ok(volume_names.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    top_level_from_tree(&dir_tree)
 }
 
+fn list(source_str: &str) -> Result<(), Error>{
+    let reader = open_archive_reader(source_str)?;
+    let mut archive = Archive::new(reader);
 
-fn restore(source_str: &str) -> Result<(), Error>{
+    let mut order: Vec<String> = Vec::new();
+    let mut stats: std::collections::HashMap<String, (u64, u64)> = std::collections::HashMap::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+        let size = entry.header().size()?;
+        if let Some(volume) = volume_of(&path) {
+            let volume = volume.to_utf8()?.to_string();
+            let stat = stats.entry(volume.clone()).or_insert_with(|| {
+                order.push(volume);
+                (0, 0)
+            });
+            stat.0 += 1;
+            stat.1 += size;
+        }
+    }
 
-    let volume_names = get_tar_top_level_list(source_str)?;
-    let volume_args = volume_names.iter().map(|volume_name| format!("--volume={}:/output/{}:rw", volume_name, volume_name));
+    for volume in &order {
+        let (files, bytes) = stats[volume];
+        println!("{}\t{} files\t{} bytes", volume, files, bytes);
+    }
+    Ok(())
+}
 
-    let (source_parent_abs, source_filename) = resolve_path(source_str, false)?;
+fn verify(source_str: &str) -> Result<(), Error>{
+    let reader = open_archive_reader(source_str)?;
+    let mut archive = Archive::new(reader);
+
+    let mut tree: Vec<PathBuf> = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry.map_err(|e| format!("Corrupt archive: failed to read entry header: {}", e))?;
+        let path = entry.path()?.into_owned();
+        // Read the whole body so the decompressor and checksum are exercised.
+        std::io::copy(&mut entry, &mut std::io::sink())
+            .map_err(|e| format!("Corrupt archive: failed to decode {:?}: {}", os_str_to_str(&path), e))?;
+        tree.push(path);
+    }
+
+    // Resolve volumes by the same rule `restore` mounts by.
+    let volumes = top_level_from_tree(&tree)?;
+    if volumes.is_empty() {
+        return Err("Archive contains no recognizable volumes".into());
+    }
+    println!("ok: {} volume(s) recognized", volumes.len());
+    Ok(())
+}
+
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut i = 0;
+    while i < 256 {
+        // xorshift64
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        table[i] = seed;
+        i += 1;
+    }
+    table
+}
+
+const GEAR: [u64; 256] = gear_table();
+
+struct Chunker {
+    min: usize,
+    avg: usize,
+    max: usize,
+    mask_s: u64,
+    mask_l: u64,
+}
+
+impl Chunker {
+    fn new(min: usize, avg: usize, max: usize) -> Self {
+        let bits = (avg as f64).log2().round() as u32;
+        Chunker {
+            min,
+            avg,
+            max,
+            // Normalization level 2: +/- 2 bits around the average.
+            mask_s: (1u64 << (bits + 2)) - 1,
+            mask_l: (1u64 << (bits - 2)) - 1,
+        }
+    }
+
+    fn next_boundary(&self, data: &[u8]) -> usize {
+        let len = data.len();
+        if len <= self.min {
+            return len;
+        }
+        let mut fp: u64 = 0;
+        let mut i = self.min;
+        let normal = self.avg.min(len);
+        while i < normal {
+            fp = (fp << 1).wrapping_add(GEAR[data[i] as usize]);
+            if fp & self.mask_s == 0 {
+                return i;
+            }
+            i += 1;
+        }
+        let hard = self.max.min(len);
+        while i < hard {
+            fp = (fp << 1).wrapping_add(GEAR[data[i] as usize]);
+            if fp & self.mask_l == 0 {
+                return i;
+            }
+            i += 1;
+        }
+        hard
+    }
+}
+
+struct Manifest {
+    chunks: Vec<String>,
+    entries: Vec<String>,
+}
+
+impl Manifest {
+    fn serialize(&self) -> String {
+        let mut out = String::from("chunks\n");
+        for c in &self.chunks {
+            out.push_str(c);
+            out.push('\n');
+        }
+        out.push_str("entries\n");
+        for e in &self.entries {
+            out.push_str(e);
+            out.push('\n');
+        }
+        out
+    }
+
+    fn parse(text: &str) -> Result<Self, Error> {
+        let mut chunks = Vec::new();
+        let mut entries = Vec::new();
+        let mut section = "";
+        for line in text.lines() {
+            match line {
+                "chunks" | "entries" => section = line,
+                "" => {}
+                value => match section {
+                    "chunks" => chunks.push(value.to_string()),
+                    "entries" => entries.push(value.to_string()),
+                    _ => return Err("Malformed manifest: data before section header".into()),
+                },
+            }
+        }
+        Ok(Manifest { chunks, entries })
+    }
+}
+
+struct ChunkStore {
+    root: PathBuf,
+}
+
+impl ChunkStore {
+    fn new(repo_dir: &str) -> Result<Self, Error> {
+        let root = PathBuf::from(repo_dir);
+        std::fs::create_dir_all(root.join("chunks"))?;
+        std::fs::create_dir_all(root.join("snapshots"))?;
+        Ok(ChunkStore { root })
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        self.root.join("chunks").join(hash)
+    }
+
+    fn ingest<R: Read>(&self, mut reader: R, chunker: &Chunker) -> Result<Vec<String>, Error> {
+        use sha2::{Digest, Sha256};
+
+        let mut hashes = Vec::new();
+        let mut buf: Vec<u8> = Vec::new();
+        let mut tmp = [0u8; 1 << 16];
+        let mut eof = false;
+        loop {
+            while !eof && buf.len() < chunker.max {
+                let n = reader.read(&mut tmp)?;
+                if n == 0 {
+                    eof = true;
+                } else {
+                    buf.extend_from_slice(&tmp[..n]);
+                }
+            }
+            if buf.is_empty() {
+                break;
+            }
+            let cut = chunker.next_boundary(&buf);
+            let chunk: Vec<u8> = buf.drain(..cut).collect();
+            let hash = format!("{:x}", Sha256::digest(&chunk));
+            let path = self.chunk_path(&hash);
+            if !path.exists() {
+                std::fs::write(&path, &chunk)?;
+            }
+            hashes.push(hash);
+        }
+        Ok(hashes)
+    }
+
+    fn write_snapshot(&self, name: &str, manifest: &Manifest) -> Result<(), Error> {
+        std::fs::write(self.root.join("snapshots").join(name), manifest.serialize())?;
+        Ok(())
+    }
+
+    fn read_snapshot(&self, name: &str) -> Result<Manifest, Error> {
+        let path = self.root.join("snapshots").join(name);
+        let text = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read snapshot {}: {}", name, e))?;
+        Manifest::parse(&text)
+    }
+}
+
+struct ChunkReader<'a> {
+    store: &'a ChunkStore,
+    hashes: std::vec::IntoIter<String>,
+    current: std::io::Cursor<Vec<u8>>,
+}
+
+impl<'a> ChunkReader<'a> {
+    fn new(store: &'a ChunkStore, hashes: Vec<String>) -> Self {
+        ChunkReader {
+            store,
+            hashes: hashes.into_iter(),
+            current: std::io::Cursor::new(Vec::new()),
+        }
+    }
+}
+
+impl<'a> Read for ChunkReader<'a> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let n = self.current.read(out)?;
+            if n > 0 {
+                return Ok(n);
+            }
+            match self.hashes.next() {
+                None => return Ok(0),
+                Some(hash) => {
+                    let body = std::fs::read(self.store.chunk_path(&hash))?;
+                    self.current = std::io::Cursor::new(body);
+                }
+            }
+        }
+    }
+}
+
+fn backup_incremental(volume_names: Vec<&str>, snapshot_name: &str, repo_dir: &str, image: &str, pull: bool) -> Result<(), Error> {
+    let image = prepare_image(image, pull, &["tar"])?;
+
+    let volume_args = volume_names.iter().map(|volume_name| format!("--volume={}:/input/{}:ro", volume_name, volume_name));
 
-    std::process::Command::new("docker")
+    let mut child = std::process::Command::new("docker")
         .arg("run")
         .arg("--rm")
-        .arg(format!("--volume={}:/input", source_parent_abs))
         .args(volume_args)
-        .arg("alpine")
+        .arg(&image)
+        .arg("tar")
+        .arg("-cf")
+        .arg("-")
+        .arg("-C")
+        .arg("/input")
+        .arg(".")
+        .stdout(std::process::Stdio::piped())
+        .spawn()?;
+
+    let stdout = child.stdout.take().ok_or("Failed to capture docker stdout")?;
+    let store = ChunkStore::new(repo_dir)?;
+    let chunker = Chunker::new(2 * 1024, 8 * 1024, 64 * 1024);
+    let chunks = store.ingest(stdout, &chunker)?;
+    wait_ok(&mut child)?;
+
+    // Derive entry paths from the stored chunks so the manifest is self-describing.
+    let tree = tar_entry_paths(ChunkReader::new(&store, chunks.clone()))?;
+    let entries = tree.iter().map(|p| os_str_to_str(&p)).collect::<Vec<_>>();
+
+    store.write_snapshot(snapshot_name, &Manifest { chunks, entries })?;
+    Ok(())
+}
+
+fn restore_incremental(snapshot_name: &str, repo_dir: &str, maps: &[(String, String)], allow: &[String], image: &str, pull: bool) -> Result<(), Error> {
+    let image = prepare_image(image, pull, &["tar"])?;
+
+    let store = ChunkStore::new(repo_dir)?;
+    let manifest = store.read_snapshot(snapshot_name)?;
+
+    let tree = manifest.entries.iter().map(PathBuf::from).collect::<Vec<_>>();
+    let volume_names = top_level_from_tree(&tree)?;
+    let volume_args = resolve_volume_args(&volume_names, maps, allow)?;
+
+    let mut child = std::process::Command::new("docker")
+        .arg("run")
+        .arg("--rm")
+        .arg("-i")
+        .args(volume_args)
+        .arg(&image)
+        .arg("tar")
+        .arg("-xf")
+        .arg("-")
+        .arg("-C")
+        .arg("/output")
+        .stdin(std::process::Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().ok_or("Failed to capture docker stdin")?;
+    let mut reader = ChunkReader::new(&store, manifest.chunks.clone());
+    std::io::copy(&mut reader, &mut stdin)?;
+    drop(stdin);
+    wait_ok(&mut child)?;
+    Ok(())
+}
+
+fn parse_map(s: &str) -> Result<(String, String), String> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(archived), Some(target)) if !archived.is_empty() && !target.is_empty() =>
+            Ok((archived.to_string(), target.to_string())),
+        _ => Err(format!("Expected <archived>=<target>, got {:?}", s)),
+    }
+}
+
+fn resolve_volume_args(archived: &[String], maps: &[(String, String)], allow: &[String]) -> Result<Vec<String>, Error> {
+    let present: std::collections::HashSet<&str> = archived.iter().map(|s| s.as_str()).collect();
+
+    let selected: Vec<String> = if allow.is_empty() {
+        archived.to_vec()
+    } else {
+        for name in allow {
+            if !present.contains(name.as_str()) {
+                return Err(format!("Requested volume {:?} is not present in the archive", name).into());
+            }
+        }
+        allow.to_vec()
+    };
+
+    let mapping: std::collections::HashMap<&str, &str> = maps.iter().map(|(a, t)| (a.as_str(), t.as_str())).collect();
+    for (archived_name, _) in maps {
+        if !present.contains(archived_name.as_str()) {
+            return Err(format!("Remapped source volume {:?} is not present in the archive", archived_name).into());
+        }
+    }
+
+    Ok(selected.iter().map(|archived_name| {
+        let target = mapping.get(archived_name.as_str()).copied().unwrap_or(archived_name.as_str());
+        format!("--volume={}:/output/{}:rw", target, archived_name)
+    }).collect())
+}
+
+fn restore_encrypted(source_str: &str, maps: &[(String, String)], allow: &[String], image: &str, pull: bool) -> Result<(), Error> {
+    // Decryption and decompression happen in Rust, so the container only needs tar.
+    let image = prepare_image(image, pull, &["tar"])?;
+
+    let passphrase = get_passphrase()?;
+
+    // Derive the key once; both decrypt passes below reuse it.
+    let key = {
+        let mut file = std::fs::File::open(source_str)?;
+        let header = EncHeader::read(&mut file)?;
+        derive_key(&passphrase, &header.salt, header.params)?
+    };
+    let open_decryptor = |source: &str| -> Result<DecryptReader<std::fs::File>, Error> {
+        let mut file = std::fs::File::open(source)?;
+        let header = EncHeader::read(&mut file)?;
+        DecryptReader::with_key(file, &header, &key)
+    };
+
+    // First pass: resolve the volumes to mount.
+    let listing = sniff_decoder(open_decryptor(source_str)?)?;
+    let tree = tar_entry_paths(listing)?;
+    let volume_names = top_level_from_tree(&tree)?;
+    let volume_args = resolve_volume_args(&volume_names, maps, allow)?;
+
+    let mut child = std::process::Command::new("docker")
+        .arg("run")
+        .arg("--rm")
+        .arg("-i")
+        .args(volume_args)
+        .arg(&image)
         .arg("tar")
-        .arg("-xzf")
-        .arg(format!("/input/{}", source_filename))
+        .arg("-xf")
+        .arg("-")
         .arg("-C")
         .arg("/output")
-        .spawn()?
-        .wait()?;
+        .stdin(std::process::Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().ok_or("Failed to capture docker stdin")?;
+    let mut reader = sniff_decoder(open_decryptor(source_str)?)?;
+    std::io::copy(&mut reader, &mut stdin)?;
+    drop(stdin);
+    wait_ok(&mut child)?;
+    Ok(())
+}
+
+fn restore(source_str: &str, maps: &[(String, String)], allow: &[String], image: &str, pull: bool) -> Result<(), Error>{
+
+    if is_encrypted(source_str)? {
+        return restore_encrypted(source_str, maps, allow, image, pull);
+    }
+
+    let volume_names = get_tar_top_level_list(source_str)?;
+    let volume_args = resolve_volume_args(&volume_names, maps, allow)?;
+
+    let (source_parent_abs, source_filename) = resolve_path(source_str, false)?;
+
+    // Decompress with the codec sniffed from the archive's magic bytes.
+    let codec = Compression::detect(source_str)?;
+    let image = prepare_image(image, pull, &required_binaries(codec))?;
+    let pipeline = format!(
+        "{} /input/{} | tar -xf - -C /output",
+        codec.decompress_cmd(),
+        shell_quote(&source_filename),
+    );
+
+    let mut child = std::process::Command::new("docker")
+        .arg("run")
+        .arg("--rm")
+        .arg(format!("--volume={}:/input", source_parent_abs))
+        .args(volume_args)
+        .arg(&image)
+        .arg("sh")
+        .arg("-c")
+        .arg(pipeline)
+        .spawn()?;
+    wait_ok(&mut child)?;
     Ok(())
 }
 
@@ -140,19 +1100,158 @@ enum Opt {
         volume_names: Vec<String>,
         #[structopt(name = "target", required = true)]
         target: String,
+        #[structopt(long = "compression", default_value = "gzip")]
+        compression: Compression,
+        #[structopt(long = "level", default_value = "6")]
+        level: u32,
+        #[structopt(long = "threads", default_value = "1")]
+        threads: u32,
+        #[structopt(long = "window")]
+        window: Option<u32>,
+        #[structopt(long = "incremental")]
+        incremental: Option<String>,
+        #[structopt(long = "encrypt")]
+        encrypt: bool,
+        #[structopt(long = "image", default_value = "alpine")]
+        image: String,
+        #[structopt(long = "pull")]
+        pull: bool,
     },
     #[structopt(name = "restore")]
     Restore {
         #[structopt(name = "source", required = true)]
         source: String,
+        #[structopt(name = "volume")]
+        volumes: Vec<String>,
+        #[structopt(long = "map", parse(try_from_str = parse_map))]
+        maps: Vec<(String, String)>,
+        #[structopt(long = "incremental")]
+        incremental: Option<String>,
+        #[structopt(long = "image", default_value = "alpine")]
+        image: String,
+        #[structopt(long = "pull")]
+        pull: bool,
+    },
+    #[structopt(name = "list")]
+    List {
+        #[structopt(name = "source", required = true)]
+        source: String,
+    },
+    #[structopt(name = "verify")]
+    Verify {
+        #[structopt(name = "source", required = true)]
+        source: String,
     },
 }
 
 fn main() -> Result<(), Error> {
     let opt = Opt::from_args();
     match opt {
-        Opt::Backup { volume_names, target } => backup(volume_names.iter().map(|s| s.as_str()).collect(), &target)?,
-        Opt::Restore { source } => restore( &source)?,
+        Opt::Backup { volume_names, target, compression, level, threads, window, incremental, encrypt, image, pull } => {
+            let volume_names = volume_names.iter().map(|s| s.as_str()).collect();
+            match incremental {
+                Some(repo_dir) => {
+                    // The incremental chunk store has no compression/encryption path.
+                    if encrypt || compression != Compression::Gzip || level != 6 || threads != 1 || window.is_some() {
+                        return Err("--incremental cannot be combined with --encrypt, --compression, --level, --threads, or --window".into());
+                    }
+                    backup_incremental(volume_names, &target, &repo_dir, &image, pull)?
+                }
+                None => backup(volume_names, &target, compression, level, threads, window, encrypt, &image, pull)?,
+            }
+        }
+        Opt::Restore { source, volumes, maps, incremental, image, pull } => match incremental {
+            Some(repo_dir) => restore_incremental(&source, &repo_dir, &maps, &volumes, &image, pull)?,
+            None => restore(&source, &maps, &volumes, &image, pull)?,
+        },
+        Opt::List { source } => list(&source)?,
+        Opt::Verify { source } => verify(&source)?,
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn pseudo_random(len: usize, mut seed: u64) -> Vec<u8> {
+        let mut out = Vec::with_capacity(len);
+        for _ in 0..len {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            out.push((seed & 0xff) as u8);
+        }
+        out
+    }
+
+    #[test]
+    fn encrypt_decrypt_round_trip() {
+        // Span several frames plus a partial trailing frame.
+        let data = pseudo_random(FRAME_SIZE * 2 + 123, 0x1234_5678);
+        let mut sealed = Vec::new();
+        encrypt_stream(&data[..], &mut sealed, "correct horse").unwrap();
+        assert_eq!(&sealed[..ENC_MAGIC.len()], ENC_MAGIC);
+
+        let mut reader = DecryptReader::new(std::io::Cursor::new(sealed), "correct horse").unwrap();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn decrypt_rejects_truncation() {
+        let data = pseudo_random(FRAME_SIZE + 500, 0x99);
+        let mut sealed = Vec::new();
+        encrypt_stream(&data[..], &mut sealed, "pw").unwrap();
+        // Drop the tail so the final-framed marker is lost.
+        sealed.truncate(sealed.len() - 100);
+
+        let mut reader = DecryptReader::new(std::io::Cursor::new(sealed), "pw").unwrap();
+        let mut out = Vec::new();
+        assert!(reader.read_to_end(&mut out).is_err());
+    }
+
+    #[test]
+    fn decrypt_rejects_wrong_passphrase() {
+        let data = pseudo_random(1000, 0x7);
+        let mut sealed = Vec::new();
+        encrypt_stream(&data[..], &mut sealed, "right").unwrap();
+
+        let mut reader = DecryptReader::new(std::io::Cursor::new(sealed), "wrong").unwrap();
+        let mut out = Vec::new();
+        assert!(reader.read_to_end(&mut out).is_err());
+    }
+
+    #[test]
+    fn chunker_round_trip_is_lossless() {
+        let data = pseudo_random(512 * 1024, 0x00ab_cdef);
+        let chunker = Chunker::new(2 * 1024, 8 * 1024, 64 * 1024);
+
+        let mut buf = data.clone();
+        let mut reassembled = Vec::new();
+        let mut chunks = 0;
+        while !buf.is_empty() {
+            let cut = chunker.next_boundary(&buf);
+            assert!(cut > 0 && cut <= buf.len());
+            assert!(cut <= chunker.max);
+            reassembled.extend_from_slice(&buf[..cut]);
+            buf.drain(..cut);
+            chunks += 1;
+        }
+        assert_eq!(reassembled, data);
+        assert!(chunks > 1, "expected multiple content-defined chunks");
+    }
+
+    #[test]
+    fn manifest_serialize_parse_round_trip() {
+        let manifest = Manifest {
+            chunks: vec!["aaaa".to_string(), "bbbb".to_string(), "cccc".to_string()],
+            entries: vec!["./vol/".to_string(), "./vol/file".to_string()],
+        };
+        let parsed = Manifest::parse(&manifest.serialize()).unwrap();
+        assert_eq!(parsed.chunks, manifest.chunks);
+        assert_eq!(parsed.entries, manifest.entries);
+    }
+}